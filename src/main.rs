@@ -1,33 +1,396 @@
 use serialport::{SerialPort, DataBits, Parity, StopBits, FlowControl};
+use serde::Deserialize;
 use std::error::Error;
 use std::io::{self, Write, Read, BufRead, BufReader};
 use std::fs::OpenOptions;
 use std::time::{Duration, Instant};
+use std::sync::mpsc;
 use std::thread;
 use chrono::{Local, Timelike};
 use serde_json::json;
+use rand::Rng;
 
-/// Enum for switching between Binary or Text mode.
-#[derive(Debug, Clone, Copy)]
+/// Enum for switching between Binary, Text, or Simulation mode.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum Mode {
     Binary,
     Text,
+    Simulation,
 }
 
-/// **Change this variable to switch between Binary and Text mode**
-const SENSOR_MODE: Mode = Mode::Text;
-
-/// **Change this variable to control JSON file creation frequency (in minutes)**
-const JSON_FILE_INTERVAL_MINUTES: u64 = 2; // Change to 5, 2, or any other value.
-const PORTS: [&str; 3] = ["/dev/ttyACM1", "/dev/ttyACM0", "/dev/ttyACM2"];
-const BAUD_RATE: u32 = 115200;
-//const SAMPLING_INTERVAL_MS: u64 = 25; // 40 Hz sampling rate
-const SAMPLING_INTERVAL_MS: u64 = 5; // 200 Hz sampling rate
-const MAX_DISTANCE_MM: u16 = 6000; // Maximum valid distance in mm
-const MIN_DISTANCE_MM: u16 = 500; // Minimum valid distance in mm
 const TEXT_MODE_COMMAND: [u8; 4] = [0x00, 0x11, 0x01, 0x45];
 const BINARY_MODE_COMMAND: [u8; 4] = [0x00, 0x11, 0x02, 0x4C];
 
+/// Runtime-tunable settings, loaded by `load_config` from built-in defaults,
+/// an optional `--config <path>` TOML/JSON file, and individual CLI flags,
+/// in that increasing order of priority. This replaces the old compile-time
+/// consts so the binary can be redeployed against a new sensor setup without
+/// a rebuild.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct Config {
+    mode: Mode,
+    /// How often (in minutes) to start a new JSON output file.
+    json_file_interval_minutes: u64,
+    /// Explicit candidate ports to probe; empty means scan all available ports.
+    ports: Vec<String>,
+    baud_rate: u32,
+    sampling_interval_ms: u64,
+    min_distance_mm: u16,
+    max_distance_mm: u16,
+    /// Shape of the per-sensor JSON object written to `sensor_readings`.
+    output_format: OutputFormat,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Text,
+            json_file_interval_minutes: 2,
+            ports: Vec::new(),
+            baud_rate: 115200,
+            sampling_interval_ms: 5, // 200 Hz sampling rate
+            min_distance_mm: 500,
+            max_distance_mm: 6000,
+            output_format: OutputFormat::Simple,
+        }
+    }
+}
+
+/// Parses a TOML or JSON config file (selected by extension, defaulting to
+/// TOML) into a `Config`, falling back to defaults for any field it omits.
+fn load_config_file(path: &str) -> Result<Config, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    if path.ends_with(".json") {
+        Ok(serde_json::from_str(&contents)?)
+    } else {
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+/// Applies `--flag value` CLI overrides on top of an existing config.
+fn apply_cli_overrides(config: &mut Config, args: &[String]) {
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--mode" => {
+                if let Some(value) = args.get(i + 1) {
+                    match value.as_str() {
+                        "text" => config.mode = Mode::Text,
+                        "binary" => config.mode = Mode::Binary,
+                        "simulation" => config.mode = Mode::Simulation,
+                        other => eprintln!("Unknown --mode value: {}", other),
+                    }
+                    i += 1;
+                }
+            }
+            "--baud-rate" => {
+                if let Some(value) = args.get(i + 1).and_then(|v| v.parse().ok()) {
+                    config.baud_rate = value;
+                    i += 1;
+                }
+            }
+            "--sampling-interval-ms" => {
+                if let Some(value) = args.get(i + 1).and_then(|v| v.parse().ok()) {
+                    config.sampling_interval_ms = value;
+                    i += 1;
+                }
+            }
+            "--json-file-interval-minutes" => {
+                if let Some(value) = args.get(i + 1).and_then(|v| v.parse().ok()) {
+                    config.json_file_interval_minutes = value;
+                    i += 1;
+                }
+            }
+            "--min-distance-mm" => {
+                if let Some(value) = args.get(i + 1).and_then(|v| v.parse().ok()) {
+                    config.min_distance_mm = value;
+                    i += 1;
+                }
+            }
+            "--max-distance-mm" => {
+                if let Some(value) = args.get(i + 1).and_then(|v| v.parse().ok()) {
+                    config.max_distance_mm = value;
+                    i += 1;
+                }
+            }
+            "--port" => {
+                if let Some(value) = args.get(i + 1) {
+                    config.ports.push(value.clone());
+                    i += 1;
+                }
+            }
+            "--output-format" => {
+                if let Some(value) = args.get(i + 1) {
+                    match value.as_str() {
+                        "simple" => config.output_format = OutputFormat::Simple,
+                        "mavlink" => config.output_format = OutputFormat::Mavlink,
+                        other => eprintln!("Unknown --output-format value: {}", other),
+                    }
+                    i += 1;
+                }
+            }
+            "--config" => {
+                i += 1; // The path itself is handled by `load_config`.
+            }
+            other => eprintln!("Ignoring unknown argument: {}", other),
+        }
+        i += 1;
+    }
+}
+
+/// Loads runtime configuration: built-in defaults, overridden by an optional
+/// `--config <path>` TOML/JSON file, overridden by individual CLI flags
+/// (`--mode`, `--baud-rate`, `--sampling-interval-ms`,
+/// `--json-file-interval-minutes`, `--min-distance-mm`, `--max-distance-mm`,
+/// `--port <path>` (repeatable), `--output-format`).
+fn load_config() -> Config {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut config = Config::default();
+
+    let config_path = args.iter().position(|a| a == "--config").and_then(|i| args.get(i + 1));
+    if let Some(path) = config_path {
+        match load_config_file(path) {
+            Ok(file_config) => config = file_config,
+            Err(e) => eprintln!("Failed to load config file {}: {}", path, e),
+        }
+    }
+
+    apply_cli_overrides(&mut config, &args);
+    config
+}
+
+/// Rejects config combinations that would otherwise panic or misbehave
+/// downstream (a zero file-rotation interval divides by zero in
+/// `save_to_json`, inverted distance bounds panic `f64::clamp`, and a zero
+/// baud rate can't open a serial port).
+fn validate_config(config: &Config) -> Result<(), Box<dyn Error>> {
+    if config.json_file_interval_minutes == 0 {
+        return Err("json_file_interval_minutes must be greater than 0".into());
+    }
+    if config.min_distance_mm >= config.max_distance_mm {
+        return Err("min_distance_mm must be less than max_distance_mm".into());
+    }
+    if config.baud_rate == 0 {
+        return Err("baud_rate must be greater than 0".into());
+    }
+    Ok(())
+}
+
+// **Simulation mode tuning** - only used when `config.mode` is `Mode::Simulation`.
+const SIMULATION_SENSOR_COUNT: usize = 3; // Number of virtual sensors to synthesize
+const SIMULATION_SWEEP_PERIOD_SECS: f64 = 10.0; // Time for a full min -> max -> min sweep
+const SIMULATION_NOISE_STDDEV_MM: f64 = 15.0; // Gaussian noise standard deviation
+const SIMULATION_DROPOUT_PROBABILITY: f64 = 0.02; // Chance a given sample is dropped (None)
+
+// **Sensor discovery tuning**
+const PORT_PROBE_ATTEMPTS: usize = 5; // How many read attempts to wait for a response while probing
+const PORT_PROBE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// **Velocity outlier filter tuning**
+const MAX_VELOCITY_MM_PER_S: f64 = 8000.0; // Reject jumps faster than this
+const VELOCITY_FILTER_STALE_TIMEOUT: Duration = Duration::from_millis(1000); // Drop held value if no valid sample arrives in time
+
+/// Selects the shape of the per-sensor JSON object written to `sensor_readings`.
+/// Selectable via `config.output_format` (`--output-format`/config file).
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// The original `distance_mm`/`distance_cm` shape, plus the velocity
+    /// filter's `filtered` flag.
+    Simple,
+    /// A richer shape mirroring the MAVLink `DISTANCE_SENSOR` message, for
+    /// consumption by flight-stack-style robotics tooling.
+    Mavlink,
+}
+
+/// Mirrors MAVLink's `MAV_DISTANCE_SENSOR` enum: what kind of rangefinder produced the reading.
+#[derive(Debug, Clone, Copy)]
+enum SensorType {
+    Laser,
+}
+
+/// Mirrors MAVLink's `MAV_SENSOR_ORIENTATION` enum: which way the sensor faces on the vehicle.
+#[derive(Debug, Clone, Copy)]
+enum Orientation {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Orientation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Orientation::Forward => "forward",
+            Orientation::Backward => "backward",
+            Orientation::Left => "left",
+            Orientation::Right => "right",
+            Orientation::Up => "up",
+            Orientation::Down => "down",
+        }
+    }
+}
+
+impl SensorType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SensorType::Laser => "laser",
+        }
+    }
+}
+
+/// Orientation assigned to sensors in discovery order; any sensor beyond
+/// this list defaults to `Orientation::Forward`.
+const PORT_ORIENTATIONS: [Orientation; 6] = [
+    Orientation::Forward,
+    Orientation::Left,
+    Orientation::Right,
+    Orientation::Backward,
+    Orientation::Up,
+    Orientation::Down,
+];
+
+/// Fixed covariance estimate (mm^2) reported alongside each reading, since
+/// the sensors don't expose a live noise estimate of their own.
+const DISTANCE_COVARIANCE_MM2: f64 = 225.0; // (15mm stddev)^2
+
+/// A synthetic per-sensor signal generator used by `Mode::Simulation` so the
+/// rest of the pipeline (validation, file rotation, multi-sensor aggregation)
+/// can be exercised without any real serial hardware attached.
+struct SimulatedSensor {
+    start: Instant,
+}
+
+impl SimulatedSensor {
+    fn new() -> Self {
+        Self { start: Instant::now() }
+    }
+
+    /// Produces a synthetic distance reading: a sine sweep between
+    /// `config.min_distance_mm` and `config.max_distance_mm` plus Gaussian
+    /// noise, with a `SIMULATION_DROPOUT_PROBABILITY` chance of returning
+    /// `None` to simulate a dropped sample. The dropout probability is a
+    /// compile-time constant; it is not read from `Config`.
+    fn sample(&self, config: &Config) -> Option<u16> {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(SIMULATION_DROPOUT_PROBABILITY) {
+            return None;
+        }
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let phase = (elapsed / SIMULATION_SWEEP_PERIOD_SECS) * std::f64::consts::TAU;
+        let mid = (config.min_distance_mm as f64 + config.max_distance_mm as f64) / 2.0;
+        let amplitude = (config.max_distance_mm as f64 - config.min_distance_mm as f64) / 2.0;
+        let signal = mid + amplitude * phase.sin();
+
+        // Box-Muller transform for approximately Gaussian noise.
+        let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+        let u2: f64 = rng.gen_range(0.0..1.0);
+        let noise = SIMULATION_NOISE_STDDEV_MM
+            * (-2.0 * u1.ln()).sqrt()
+            * (std::f64::consts::TAU * u2).cos();
+
+        let distance = (signal + noise).clamp(config.min_distance_mm as f64, config.max_distance_mm as f64);
+        Some(distance.round() as u16)
+    }
+}
+
+/// A sensor input, either a real serial port or a synthetic one used in
+/// `Mode::Simulation`.
+enum SensorSource {
+    Real(Box<dyn SerialPort>),
+    Simulated(SimulatedSensor),
+}
+
+/// Rejects physically impossible jumps between consecutive readings of a
+/// single sensor. Readings occasionally glitch to wildly wrong values that
+/// still fall inside `[config.min_distance_mm, config.max_distance_mm]`, so this tracks the
+/// last accepted distance and discards anything that implies a velocity
+/// above `MAX_VELOCITY_MM_PER_S`, holding the previous value instead.
+struct VelocityFilter {
+    last_accepted: Option<u16>,
+    last_accepted_at: Option<Instant>,
+}
+
+impl VelocityFilter {
+    fn new() -> Self {
+        Self { last_accepted: None, last_accepted_at: None }
+    }
+
+    /// Applies the filter to a freshly read (and already range-validated)
+    /// sample. Returns the distance to report and whether it was held
+    /// (i.e. `sample` was rejected or missing).
+    fn apply(&mut self, sample: Option<u16>) -> (u16, bool) {
+        let now = Instant::now();
+
+        // If no valid sample has arrived for too long, stop reporting the
+        // held value so stale data isn't reported forever.
+        if let Some(last_accepted_at) = self.last_accepted_at {
+            if now.duration_since(last_accepted_at) > VELOCITY_FILTER_STALE_TIMEOUT {
+                self.last_accepted = None;
+                self.last_accepted_at = None;
+            }
+        }
+
+        let new_distance = match sample {
+            Some(d) => d,
+            None => return (self.last_accepted.unwrap_or(0), true),
+        };
+
+        let within_limit = match (self.last_accepted, self.last_accepted_at) {
+            (Some(last), Some(last_at)) => {
+                let dt_secs = now.duration_since(last_at).as_secs_f64().max(f64::EPSILON);
+                let velocity = (new_distance as f64 - last as f64).abs() / dt_secs;
+                velocity <= MAX_VELOCITY_MM_PER_S
+            }
+            // The very first accepted sample bypasses the check.
+            _ => true,
+        };
+
+        if within_limit {
+            self.last_accepted = Some(new_distance);
+            self.last_accepted_at = Some(now);
+            (new_distance, false)
+        } else {
+            (self.last_accepted.unwrap_or(0), true)
+        }
+    }
+}
+
+// **Binary frame format**: header byte, reserved byte, distance hi/lo bytes.
+// This matches the original fixed `read_exact`-sized frame the baseline
+// parsed; no checksum byte has ever been confirmed on the wire for this
+// sensor, so we don't invent one here. Resilience instead comes from
+// scanning for the header byte (below) rather than assuming it always
+// lands at the start of a read, plus the existing min/max range check
+// callers already apply to the parsed distance.
+const BINARY_FRAME_HEADER: u8 = 0x54;
+const BINARY_FRAME_LEN: usize = 4;
+
+/// Scans `buf` for a frame starting with `BINARY_FRAME_HEADER`, consumes it,
+/// and returns the parsed distance (in mm, converted from 0.1mm units). Drops
+/// any bytes before the header so a dropped or shifted byte can't
+/// permanently desynchronize the stream. Bytes that don't yet form a
+/// complete frame are left in `buf` for the next read.
+fn parse_binary_frame(buf: &mut Vec<u8>) -> Option<u16> {
+    let header_pos = buf.iter().position(|&b| b == BINARY_FRAME_HEADER)?;
+    if header_pos > 0 {
+        buf.drain(0..header_pos); // Drop noise before the header.
+    }
+
+    if buf.len() < BINARY_FRAME_LEN {
+        return None; // Wait for the rest of the frame.
+    }
+
+    let distance = (buf[2] as u16) << 8 | (buf[3] as u16);
+    buf.drain(0..BINARY_FRAME_LEN);
+    Some(distance / 10) // Convert from 0.1mm to mm
+}
+
 /// Sends a command to the sensor to switch to Binary or Text mode.
 fn send_command(port: &mut Box<dyn SerialPort>, command: &[u8]) -> io::Result<()> {
     println!("Sending mode switch command...");
@@ -43,10 +406,114 @@ fn flush_serial(port: &mut Box<dyn SerialPort>) {
     let _ = port.clear(serialport::ClearBuffer::Output); // Flush output buffer
 }
 
+/// A ToF sensor confirmed during startup probing, with its own already
+/// mode-switched, already-open serial port.
+struct DiscoveredSensor {
+    id: String,
+    port_name: String,
+    port: Box<dyn SerialPort>,
+}
+
+/// Probes a single candidate port: opens it, sends the mode-switch command,
+/// then waits briefly to see whether a parseable, in-range distance frame
+/// comes back. Returns the open port if the sensor answered.
+fn probe_port(port_name: &str, config: &Config) -> Option<Box<dyn SerialPort>> {
+    let mut port = match serialport::new(port_name, config.baud_rate)
+        .timeout(Duration::from_millis(500))
+        .data_bits(DataBits::Eight)
+        .parity(Parity::None)
+        .stop_bits(StopBits::One)
+        .flow_control(FlowControl::None)
+        .open()
+    {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Failed to open {} while probing: {}", port_name, e);
+            return None;
+        }
+    };
+
+    flush_serial(&mut port);
+
+    let command = match config.mode {
+        Mode::Binary => BINARY_MODE_COMMAND,
+        Mode::Text => TEXT_MODE_COMMAND,
+        Mode::Simulation => return None, // Simulation mode doesn't probe real hardware.
+    };
+
+    if let Err(e) = send_command(&mut port, &command) {
+        eprintln!("Failed to send probe command to {}: {}", port_name, e);
+        return None;
+    }
+
+    let mut binary_buffer = Vec::new();
+
+    for _ in 0..PORT_PROBE_ATTEMPTS {
+        let responded = match config.mode {
+            Mode::Text => read_text_distance(&mut port, config).is_some(),
+            Mode::Binary => {
+                let mut chunk = [0u8; 64];
+                if let Ok(n) = port.read(&mut chunk) {
+                    if n > 0 {
+                        binary_buffer.extend_from_slice(&chunk[..n]);
+                    }
+                }
+                matches!(parse_binary_frame(&mut binary_buffer), Some(d) if d >= config.min_distance_mm && d <= config.max_distance_mm)
+            }
+            Mode::Simulation => unreachable!(),
+        };
+
+        if responded {
+            flush_serial(&mut port); // Discard probe bytes so sampling starts clean.
+            return Some(port);
+        }
+
+        thread::sleep(PORT_PROBE_POLL_INTERVAL);
+    }
+
+    None
+}
+
+/// Discovers ToF sensors by probing each candidate port and keeping only the
+/// ones that answer with a valid in-range reading, each tagged with a
+/// stable logical ID assigned in discovery order. Candidates come from
+/// `config.ports` if set, otherwise every port `serialport::available_ports`
+/// reports, so the tool works regardless of enumeration order or count.
+fn discover_sensors(config: &Config) -> Vec<DiscoveredSensor> {
+    let mut candidate_names: Vec<String> = if !config.ports.is_empty() {
+        config.ports.clone()
+    } else {
+        match serialport::available_ports() {
+            Ok(ports) => ports.into_iter().map(|p| p.port_name).collect(),
+            Err(e) => {
+                eprintln!("Failed to enumerate serial ports: {}", e);
+                Vec::new()
+            }
+        }
+    };
+    candidate_names.sort(); // Deterministic probe order
+
+    let mut discovered = Vec::new();
+    for port_name in candidate_names {
+        println!("Probing {}...", port_name);
+
+        if let Some(port) = probe_port(&port_name, config) {
+            let id = format!("sensor{}", discovered.len());
+            println!("Confirmed ToF sensor on {} as {}", port_name, id);
+            discovered.push(DiscoveredSensor { id, port_name, port });
+        } else {
+            println!("No valid distance frame from {}, skipping", port_name);
+        }
+    }
+
+    discovered
+}
+
 /// Saves sensor data to a JSON file, creating a new file based on the user-defined interval.
-fn save_to_json(sensor_readings: &serde_json::Value, last_saved: &mut Instant) -> io::Result<()> {
+fn save_to_json(sensor_readings: &serde_json::Value, last_saved: &mut Instant, config: &Config) -> io::Result<()> {
     let now = Local::now();
-    let rounded_minute = (now.minute() / JSON_FILE_INTERVAL_MINUTES as u32) * JSON_FILE_INTERVAL_MINUTES as u32;
+    let interval = config.json_file_interval_minutes;
+    let rounded_minute = (now.minute() / interval as u32) * interval as u32;
 
     let filename = format!(
         "sensor_data_{}_{}-{:02}.json",
@@ -56,7 +523,7 @@ fn save_to_json(sensor_readings: &serde_json::Value, last_saved: &mut Instant) -
     );
 
     // Create a new file only if the configured time interval has passed
-    if last_saved.elapsed() >= Duration::from_secs(JSON_FILE_INTERVAL_MINUTES * 60) {
+    if last_saved.elapsed() >= Duration::from_secs(interval * 60) {
         *last_saved = Instant::now(); // Reset timer
     }
 
@@ -65,19 +532,46 @@ fn save_to_json(sensor_readings: &serde_json::Value, last_saved: &mut Instant) -
         .append(true) // Append if it already exists
         .open(filename)?;
 
-    writeln!(file, "{}", sensor_readings.to_string())?;
+    writeln!(file, "{}", sensor_readings)?;
     Ok(())
 }
 
+/// Builds the JSON object reported for a single sensor, in the shape
+/// selected by `config.output_format`.
+///
+/// `time_usec` is a monotonic microsecond timestamp (elapsed time since
+/// program start), matching the `time_usec` convention of MAVLink's
+/// `DISTANCE_SENSOR` message.
+fn build_distance_report(port_idx: usize, distance: u16, filtered: bool, time_usec: u64, config: &Config) -> serde_json::Value {
+    match config.output_format {
+        OutputFormat::Simple => json!({
+            "distance_mm": distance,
+            "distance_cm": distance / 10,
+            "filtered": filtered
+        }),
+        OutputFormat::Mavlink => json!({
+            "distance_mm": distance,
+            "distance_cm": distance / 10,
+            "filtered": filtered,
+            "min_distance": config.min_distance_mm,
+            "max_distance": config.max_distance_mm,
+            "type": SensorType::Laser.as_str(),
+            "orientation": PORT_ORIENTATIONS.get(port_idx).unwrap_or(&Orientation::Forward).as_str(),
+            "covariance": DISTANCE_COVARIANCE_MM2,
+            "time_usec": time_usec
+        }),
+    }
+}
+
 /// Reads a single distance value from a text-based sensor output.
-fn read_text_distance(port: &mut Box<dyn SerialPort>) -> Option<u16> {
+fn read_text_distance(port: &mut Box<dyn SerialPort>, config: &Config) -> Option<u16> {
     let mut reader = BufReader::new(port);
     let mut line = String::new();
 
     match reader.read_line(&mut line) {
         Ok(n) if n > 0 => {
             if let Ok(distance) = line.trim().parse::<u16>() {
-                if distance >= MIN_DISTANCE_MM && distance <= MAX_DISTANCE_MM {
+                if distance >= config.min_distance_mm && distance <= config.max_distance_mm {
                     return Some(distance);
                 } else {
                     println!("WARNING: Ignoring invalid distance {} mm", distance);
@@ -89,104 +583,146 @@ fn read_text_distance(port: &mut Box<dyn SerialPort>) -> Option<u16> {
     None
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut last_saved = Instant::now(); // Track the last save time
-    let mut serial_ports: Vec<Option<Box<dyn SerialPort>>> = Vec::new();
-
-    for &port_name in &PORTS {
-        match serialport::new(port_name, BAUD_RATE)
-            .timeout(Duration::from_millis(500)) // Increased timeout for reliability
-            .data_bits(DataBits::Eight)
-            .parity(Parity::None)
-            .stop_bits(StopBits::One)
-            .flow_control(FlowControl::None)
-            .open()
-        {
-            Ok(mut p) => {
-                println!("Opened serial port: {}", port_name);
-
-                // **Flush serial buffer before using it**
-                flush_serial(&mut p);
-
-                // **Send mode switch command based on the selected mode**
-                let command = match SENSOR_MODE {
-                    Mode::Binary => BINARY_MODE_COMMAND,
-                    Mode::Text => TEXT_MODE_COMMAND,
-                };
-
-                if let Err(e) = send_command(&mut p, &command) {
-                    eprintln!("Failed to send command to {}: {}", port_name, e);
+/// A single sensor's reading, pushed from its reader thread to the
+/// aggregator thread over a channel.
+struct SensorUpdate {
+    index: usize,
+    distance: u16,
+    filtered: bool,
+    time_usec: u64,
+}
+
+/// Drives one sensor: reads it (at its own pace, per `config.mode`), applies
+/// the velocity filter, and pushes the result to the aggregator. Runs on its
+/// own thread so a slow or stalled sensor can't jitter the sampling cadence
+/// of the others.
+fn run_sensor_reader(index: usize, id: String, mut source: SensorSource, config: Config, start_time: Instant, tx: mpsc::Sender<SensorUpdate>) {
+    let mut velocity_filter = VelocityFilter::new();
+    let mut binary_buffer = Vec::new();
+
+    loop {
+        let raw_sample: Option<u16> = match &mut source {
+            SensorSource::Real(port) => match config.mode {
+                Mode::Text => read_text_distance(port, &config),
+                Mode::Binary => {
+                    let mut chunk = [0u8; 64];
+                    if let Ok(n) = port.read(&mut chunk) {
+                        if n > 0 {
+                            binary_buffer.extend_from_slice(&chunk[..n]);
+                        }
+                    }
+                    match parse_binary_frame(&mut binary_buffer) {
+                        Some(raw) if raw >= config.min_distance_mm && raw <= config.max_distance_mm => Some(raw),
+                        Some(raw) => {
+                            println!("WARNING: Ignoring out-of-range distance {} mm from {}", raw, id);
+                            None
+                        }
+                        None => None,
+                    }
                 }
+                Mode::Simulation => unreachable!(),
+            },
+            SensorSource::Simulated(sim) => sim.sample(&config),
+        };
 
-                serial_ports.push(Some(p));
-            }
-            Err(e) => {
-                eprintln!("Failed to open {}: {}", port_name, e);
-                serial_ports.push(None);
-            }
+        let (distance, filtered) = velocity_filter.apply(raw_sample);
+        if filtered && raw_sample.is_some() {
+            println!("WARNING: Velocity filter rejected sample from {}, holding previous value", id);
         }
-    }
 
-    if serial_ports.is_empty() {
-        eprintln!("No serial ports available.");
-        return Err(Box::new(io::Error::new(io::ErrorKind::Other, "No ports opened")));
+        let update = SensorUpdate {
+            index,
+            distance,
+            filtered,
+            time_usec: start_time.elapsed().as_micros() as u64,
+        };
+
+        if tx.send(update).is_err() {
+            break; // Aggregator has shut down.
+        }
+
+        thread::sleep(Duration::from_millis(config.sampling_interval_ms));
     }
+}
+
+/// Assembles each tick's combined JSON from the latest update received from
+/// each sensor's reader thread and handles file rotation, decoupled from the
+/// sensors' own read cadence.
+fn run_aggregator(rx: mpsc::Receiver<SensorUpdate>, sensor_ids: &[String], config: &Config) -> io::Result<()> {
+    let mut last_saved = Instant::now();
+    let mut latest: Vec<Option<SensorUpdate>> = (0..sensor_ids.len()).map(|_| None).collect();
 
     loop {
+        match rx.recv_timeout(Duration::from_millis(config.sampling_interval_ms)) {
+            Ok(update) => {
+                let idx = update.index;
+                latest[idx] = Some(update);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        // Drain any further already-queued updates so a burst from one
+        // sensor doesn't delay this tick's report for the rest.
+        while let Ok(update) = rx.try_recv() {
+            let idx = update.index;
+            latest[idx] = Some(update);
+        }
+
         let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
         let mut sensor_readings = json!({ "timestamp": timestamp, "sensors": {} });
 
-        for (i, serial_port) in serial_ports.iter_mut().enumerate() {
-            let mut final_distance = 0;
-
-            if let Some(ref mut port) = serial_port {
-                match SENSOR_MODE {
-                    Mode::Text => {
-                        // **Read a text-mode distance value**
-                        if let Some(distance) = read_text_distance(port) {
-                            final_distance = distance;
-                            println!(
-                                "[{}] {} | Parsed Distance: {} mm ({} cm)",
-                                timestamp, PORTS[i], final_distance, final_distance / 10
-                            );
-                        }
-                    }
-                    Mode::Binary => {
-                        let mut buffer = [0u8; 4];
-                        if port.read_exact(&mut buffer).is_ok() && buffer[0] == 0x54 {
-                            let distance = (buffer[2] as u16) << 8 | (buffer[3] as u16);
-                            let distance = distance / 10; // Convert from 0.1mm to mm
-
-                            if distance >= MIN_DISTANCE_MM && distance <= MAX_DISTANCE_MM {
-                                final_distance = distance;
-                                println!(
-                                    "[{}] {} | Binary Distance: {} mm ({} cm)",
-                                    timestamp, PORTS[i], final_distance, final_distance / 10
-                                );
-                            } else {
-                                println!(
-                                    "WARNING: Ignoring out-of-range distance {} mm from {}",
-                                    distance, PORTS[i]
-                                );
-                            }
-                        }
-                    }
-                }
-            } else {
-                println!("[{}] {} is unavailable (Setting distance to 0 mm)", timestamp, PORTS[i]);
+        for (i, update) in latest.iter().enumerate() {
+            if let Some(update) = update {
+                sensor_readings["sensors"][&sensor_ids[i]] =
+                    build_distance_report(i, update.distance, update.filtered, update.time_usec, config);
             }
-
-            sensor_readings["sensors"][PORTS[i]] = json!({
-                "distance_mm": final_distance,
-                "distance_cm": final_distance / 10
-            });
         }
 
-        // Save to JSON at user-defined intervals
-        if let Err(e) = save_to_json(&sensor_readings, &mut last_saved) {
+        if let Err(e) = save_to_json(&sensor_readings, &mut last_saved, config) {
             eprintln!("Error saving to JSON: {}", e);
         }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = load_config();
+    validate_config(&config)?;
+    let start_time = Instant::now(); // Reference point for monotonic report timestamps
+    let mut sensor_ids: Vec<String> = Vec::new();
+    let mut serial_ports: Vec<SensorSource> = Vec::new();
+
+    if matches!(config.mode, Mode::Simulation) {
+        println!("Simulation mode enabled: synthesizing distance streams for {} virtual sensors", SIMULATION_SENSOR_COUNT);
+        for i in 0..SIMULATION_SENSOR_COUNT {
+            let id = format!("sensor{}", i);
+            println!("Starting virtual sensor: {}", id);
+            sensor_ids.push(id);
+            serial_ports.push(SensorSource::Simulated(SimulatedSensor::new()));
+        }
+    } else {
+        println!("Discovering ToF sensors...");
+        for discovered in discover_sensors(&config) {
+            println!("Starting sensor reader for {} on {}", discovered.id, discovered.port_name);
+            sensor_ids.push(discovered.id);
+            serial_ports.push(SensorSource::Real(discovered.port));
+        }
+    }
 
-        thread::sleep(Duration::from_millis(SAMPLING_INTERVAL_MS));
+    if serial_ports.is_empty() {
+        eprintln!("No ToF sensors discovered.");
+        return Err(Box::new(io::Error::other("No sensors discovered")));
     }
-}
\ No newline at end of file
+
+    let (tx, rx) = mpsc::channel();
+
+    for (index, (id, source)) in sensor_ids.iter().cloned().zip(serial_ports).enumerate() {
+        let config = config.clone();
+        let tx = tx.clone();
+        thread::spawn(move || run_sensor_reader(index, id, source, config, start_time, tx));
+    }
+    drop(tx); // Only the reader threads' clones should keep the channel open.
+
+    run_aggregator(rx, &sensor_ids, &config)?;
+    Ok(())
+}